@@ -1,52 +1,170 @@
 extern crate futures;
 
+mod persistence;
+
 use actix_web::Error;
 use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
 use std::time::{Duration, Instant};
 
+use actix_web::http::header;
 use actix_web::{delete, error, get, patch, post, web, HttpRequest, HttpResponse, Responder};
-use futures::StreamExt;
-use serde::Deserialize;
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
 use crate::TTL_DEFAULT;
 const MAX_SIZE: usize = 262_144; // max payload size is 256k
+const WATCH_CHANNEL_CAPACITY: usize = 1024;
+const WATCH_KEEP_ALIVE: Duration = Duration::from_secs(15);
 
 struct MapValue {
     value: serde_json::Value,
     ttl: u64,
     timestamp: Instant,
+    version: u64,
+    /// Remaining allowed `GET`s before the key is evicted, a la "burn after
+    /// N reads". `None` means no read-count limit, only the time TTL applies.
+    remaining_reads: Option<u64>,
+}
+
+/// A key is alive only while *both* its time TTL and its remaining-reads
+/// budget (if any) haven't run out.
+fn is_alive(val: &MapValue) -> bool {
+    val.timestamp.elapsed() < Duration::from_secs(val.ttl) && val.remaining_reads != Some(0)
+}
+
+/// A change notification published to `/watch` subscribers whenever a key
+/// is set or deleted.
+#[derive(Clone, Serialize)]
+struct ChangeEvent {
+    key: String,
+    op: &'static str,
+    version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
 }
 
 pub struct Map {
     map: Mutex<HashMap<String, MapValue>>,
+    changes: broadcast::Sender<ChangeEvent>,
+    store: Option<persistence::Store>,
 }
 
 impl Map {
-    pub fn new() -> Map {
-        Map {
-            map: Mutex::new(HashMap::new()),
+    /// Build a `Map`. When `persist_dir` is `Some`, the store is replayed
+    /// from its snapshot and WAL before returning so a restart picks up
+    /// where it left off; `None` keeps the original pure in-memory mode.
+    pub fn new(persist_dir: Option<PathBuf>) -> io::Result<Map> {
+        let (changes, _) = broadcast::channel(WATCH_CHANNEL_CAPACITY);
+        let (map, store) = match persist_dir {
+            Some(dir) => {
+                let store = persistence::Store::open(&dir)?;
+                let map = store.load()?;
+                (map, Some(store))
+            }
+            None => (HashMap::new(), None),
+        };
+        Ok(Map {
+            map: Mutex::new(map),
+            changes,
+            store,
+        })
+    }
+
+    /// Fold the WAL into a snapshot, if persistence is enabled. A no-op in
+    /// pure in-memory mode.
+    pub fn compact(&self) -> io::Result<()> {
+        if let Some(store) = &self.store {
+            let lock = self.map.lock().unwrap();
+            store.compact(&lock)?;
         }
+        Ok(())
+    }
+
+    /// Evict expired entries. Scans at most `batch_size` entries per call
+    /// rather than the whole map, so the lock is only held long enough to
+    /// collect and remove that bounded slice. Returns how many were reaped.
+    pub fn reap_expired(&self, batch_size: usize) -> usize {
+        let mut lock = self.map.lock().unwrap();
+        let expired: Vec<String> = lock
+            .iter()
+            .take(batch_size)
+            .filter(|(_, v)| !is_alive(v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &expired {
+            lock.remove(key);
+            on_delete(self, key.clone());
+        }
+        expired.len()
     }
 }
 
 impl Default for Map {
     fn default() -> Self {
-        Self::new()
+        Self::new(None).expect("in-memory Map::new(None) cannot fail")
     }
 }
 
+/// Record a key being set: append it to the WAL (if persistence is
+/// enabled) before publishing the change event, so the two stay ordered
+/// with the write itself under the same map lock.
+fn on_set(
+    map: &Map,
+    key: String,
+    value: serde_json::Value,
+    ttl: u64,
+    version: u64,
+    remaining_reads: Option<u64>,
+) {
+    if let Some(store) = &map.store {
+        store.append_set(&key, &value, ttl, version, remaining_reads);
+    }
+    publish_change(&map.changes, key, "set", version, Some(value));
+}
+
+/// Record a key being deleted, symmetric to [`on_set`].
+fn on_delete(map: &Map, key: String) {
+    if let Some(store) = &map.store {
+        store.append_delete(&key);
+    }
+    publish_change(&map.changes, key, "delete", 0, None);
+}
+
+/// Publish a change event. Dropped silently if nobody is currently
+/// subscribed on `/watch`.
+fn publish_change(
+    changes: &broadcast::Sender<ChangeEvent>,
+    key: String,
+    op: &'static str,
+    version: u64,
+    value: Option<serde_json::Value>,
+) {
+    let _ = changes.send(ChangeEvent {
+        key,
+        op,
+        version,
+        value,
+    });
+}
+
 #[derive(Deserialize)]
 struct SetTx {
     set: String,
     value: serde_json::Value,
     ttl: Option<u64>,
+    if_version: Option<u64>,
+    views: Option<u64>,
 }
 
 #[derive(Deserialize)]
 struct DeleteTx {
     delete: String,
+    if_version: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -71,6 +189,21 @@ enum PostData {
 #[derive(Deserialize)]
 struct InsertOpts {
     ttl: Option<u64>,
+    if_version: Option<u64>,
+    views: Option<u64>,
+}
+
+/// The version a caller wants the existing value to match before a write is
+/// allowed, taken from the `if_version` query param (preferred) or the
+/// `If-Match` header. A key that doesn't exist yet has an implicit version of `0`.
+fn requested_version(req: &HttpRequest, if_version: Option<u64>) -> Option<u64> {
+    if if_version.is_some() {
+        return if_version;
+    }
+    req.headers()
+        .get("If-Match")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.trim_matches('"').parse::<u64>().ok())
 }
 
 enum FormatEnum {
@@ -104,7 +237,10 @@ where
     match format {
         FormatEnum::Json => (if values {
             serde_json::json!(st_map
-                .map(|(k, v)| (k.clone(), serde_json::json!(&v.value)))
+                .map(|(k, v)| (
+                    k.clone(),
+                    serde_json::json!({ "value": &v.value, "version": v.version }),
+                ))
                 .collect::<serde_json::map::Map<String, serde_json::Value>>())
         } else {
             serde_json::json!(st_map.map(|(k, _)| k.as_str()).collect::<Vec<&str>>())
@@ -121,6 +257,77 @@ where
     }
 }
 
+#[derive(Deserialize)]
+struct RangeSpec {
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+    skip: Option<usize>,
+    reverse: Option<bool>,
+    values: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct BatchReadRequest {
+    #[serde(rename = "readBatch")]
+    read_batch: Vec<RangeSpec>,
+}
+
+/// Select the keys in `lock` matching a single `RangeSpec`: prefix filter,
+/// `start <= k < end` bounds, TTL liveness, lexicographic ordering
+/// (reversed if requested), then `skip`/`limit`.
+fn select_range<'a>(
+    lock: &'a HashMap<String, MapValue>,
+    range: &RangeSpec,
+) -> impl Iterator<Item = (&'a String, &'a MapValue)> {
+    let prefix = range.prefix.clone().unwrap_or_default();
+    let start = range.start.clone();
+    let end = range.end.clone();
+    let skip = range.skip.unwrap_or(0);
+    let limit = range.limit.unwrap_or_else(|| lock.len());
+
+    let mut keys: Vec<&String> = lock
+        .iter()
+        .filter(move |(k, v)| {
+            k.starts_with(&prefix)
+                && is_alive(v)
+                && start.as_ref().map_or(true, |s| *k >= s)
+                && end.as_ref().map_or(true, |e| *k < e)
+        })
+        .map(|(k, _)| k)
+        .collect();
+    keys.sort();
+    if range.reverse.unwrap_or(false) {
+        keys.reverse();
+    }
+
+    keys.into_iter()
+        .skip(skip)
+        .take(limit)
+        .filter_map(move |k| lock.get_key_value(k))
+}
+
+#[post("/batch-read")]
+async fn batch_read(payload: web::Payload, st_map: web::Data<Map>) -> Result<HttpResponse, Error> {
+    let body = read_body(payload).await?;
+    let batch = serde_json::from_slice::<BatchReadRequest>(&body)
+        .map_err(|e| error::ErrorBadRequest(format!("{}", e)))?;
+
+    let lock = st_map.map.lock().unwrap();
+    let mut results = serde_json::map::Map::new();
+    for (idx, range) in batch.read_batch.iter().enumerate() {
+        let values = range.values.unwrap_or(false);
+        let formatted = format_map(FormatEnum::Json, values, select_range(&lock, range));
+        let value: serde_json::Value = serde_json::from_str(&formatted).unwrap();
+        results.insert(idx.to_string(), value);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::Value::Object(results).to_string()))
+}
+
 #[get("/")]
 async fn list_keys(
     _req: HttpRequest,
@@ -136,47 +343,64 @@ async fn list_keys(
         None => FormatEnum::Json,
     };
     let content_type = match format {
-        FormatEnum::Text => "plain/text",
+        FormatEnum::Text => "text/plain",
         FormatEnum::Json => "application/json",
     };
-    let limit = query
-        .limit
-        .unwrap_or_else(|| st_map.map.lock().unwrap().len());
-    let skip = query.skip.unwrap_or(0);
-    let prefix = match &query.prefix {
-        Some(pref) => pref,
-        None => "",
-    };
-    let _reverse = query.reverse.unwrap_or(false);
     let values = query.values.unwrap_or(false);
+    // Reuse `select_range`'s sort-then-reverse logic instead of the ad hoc
+    // filter chain this used to have, which bound `reverse` but never
+    // applied it.
+    let range = RangeSpec {
+        prefix: query.prefix.clone(),
+        start: None,
+        end: None,
+        limit: query.limit,
+        skip: query.skip,
+        reverse: query.reverse,
+        values: Some(values),
+    };
     let key_lists = st_map.map.lock().unwrap();
     HttpResponse::Ok()
         .content_type(content_type)
-        .body(format_map(
-            format,
-            values,
-            key_lists
-                .iter()
-                .filter(|(k, _)| k.starts_with(prefix))
-                .filter(|(_, val)| val.timestamp.elapsed() < Duration::from_secs(val.ttl))
-                .skip(skip)
-                .take(limit),
-        ))
+        .body(format_map(format, values, select_range(&key_lists, &range)))
 }
 
 #[get("/{key}")]
 async fn get_val(web::Path(key): web::Path<String>, st_map: web::Data<Map>) -> impl Responder {
-    let lock = st_map.map.lock().unwrap();
-    match lock.get(&key) {
-        Some(val) => {
-            if val.timestamp.elapsed() < Duration::from_secs(val.ttl) {
-                HttpResponse::Ok().body(serde_json::to_string(&val.value).unwrap())
-            } else {
-                // Key has expired. Keeping it a different path in case this
-                // gets changed into a "there's a value but it has expired"
-                // response
-                HttpResponse::NotFound().body("Value found but expired".to_string())
+    let mut lock = st_map.map.lock().unwrap();
+    match lock.get_mut(&key) {
+        Some(val) if is_alive(val) => {
+            let body = serde_json::to_string(&val.value).unwrap();
+            let etag = val.version.to_string();
+            // Burn one read off the budget; a key that hits zero is removed
+            // immediately so it behaves exactly like a time-expired one.
+            let exhausted = match &mut val.remaining_reads {
+                Some(remaining) => {
+                    *remaining -= 1;
+                    *remaining == 0
+                }
+                None => false,
+            };
+            if exhausted {
+                lock.remove(&key);
+                on_delete(&st_map, key);
+            } else if let Some(remaining) = val.remaining_reads {
+                // Persist the decrement itself, not just the eventual
+                // delete: otherwise a restart replays the WAL's last
+                // full-budget `Set` and the key's read budget resets,
+                // letting it be served more than `views` times overall.
+                if let Some(store) = &st_map.store {
+                    let ttl_left = val.ttl.saturating_sub(val.timestamp.elapsed().as_secs());
+                    store.append_set(&key, &val.value, ttl_left, val.version, Some(remaining));
+                }
             }
+            HttpResponse::Ok().append_header(("ETag", etag)).body(body)
+        }
+        Some(_) => {
+            // Key has expired. Keeping it a different path in case this
+            // gets changed into a "there's a value but it has expired"
+            // response
+            HttpResponse::NotFound().body("Value found but expired".to_string())
         }
         None => HttpResponse::NotFound().body("No value found".to_string()),
     }
@@ -197,6 +421,7 @@ async fn read_body(mut payload: web::Payload) -> Result<web::BytesMut, error::Er
 
 #[post("/{key}")]
 async fn insert_key(
+    req: HttpRequest,
     payload: web::Payload,
     web::Path(key): web::Path<String>,
     query: web::Query<InsertOpts>,
@@ -214,16 +439,29 @@ async fn insert_key(
             HttpResponse::BadRequest().body("Transactions should be used without a key in the path")
         }
         PostData::Other(json) => {
+            let current_version = lock.get(&key).map(|v| v.version).unwrap_or(0);
+            if let Some(expected) = requested_version(&req, query.if_version) {
+                if expected != current_version {
+                    return Ok(HttpResponse::PreconditionFailed().body("Version mismatch"));
+                }
+            }
+            let version = current_version + 1;
             let timestamp = Instant::now();
+            let remaining_reads = query.views;
             lock.insert(
-                key,
+                key.clone(),
                 MapValue {
-                    value: json,
+                    value: json.clone(),
                     ttl,
                     timestamp,
+                    version,
+                    remaining_reads,
                 },
             );
-            HttpResponse::Created().body("Inserted")
+            on_set(&st_map, key, json, ttl, version, remaining_reads);
+            HttpResponse::Created()
+                .append_header(("ETag", version.to_string()))
+                .body("Inserted")
         }
     })
 }
@@ -243,8 +481,12 @@ async fn insert_key_txn(
 
     Ok(match post_data {
         PostData::TransactionSet(tx_set) => {
+            if let Some(key) = first_version_mismatch(&tx_set.txn, &lock) {
+                return Ok(HttpResponse::PreconditionFailed()
+                    .body(format!("Version mismatch for key \"{}\"", key)));
+            }
             for item in tx_set.txn {
-                apply_action(item, &mut lock, ttl);
+                apply_action(item, &mut lock, ttl, &st_map);
             }
             HttpResponse::Created().body("Applied")
         }
@@ -253,28 +495,82 @@ async fn insert_key_txn(
     })
 }
 
-fn apply_action(action: Transaction, lock: &mut MutexGuard<HashMap<String, MapValue>>, ttl: u64) {
+/// Check every `if_version` carried by a transaction batch against the
+/// current map state, returning the first key whose version doesn't match
+/// so the whole batch can be rejected before anything is applied.
+fn first_version_mismatch<'a>(
+    txn: &'a [Transaction],
+    lock: &HashMap<String, MapValue>,
+) -> Option<&'a str> {
+    txn.iter().find_map(|action| {
+        let (key, if_version) = match action {
+            Transaction::SetTx(t) => (t.set.as_str(), t.if_version),
+            Transaction::DeleteTx(t) => (t.delete.as_str(), t.if_version),
+        };
+        let expected = if_version?;
+        let current = lock.get(key).map(|v| v.version).unwrap_or(0);
+        if current != expected {
+            Some(key)
+        } else {
+            None
+        }
+    })
+}
+
+fn apply_action(
+    action: Transaction,
+    lock: &mut MutexGuard<HashMap<String, MapValue>>,
+    ttl: u64,
+    map: &Map,
+) {
     let timestamp = Instant::now();
     match action {
-        Transaction::SetTx(set_txn) => lock.insert(
-            set_txn.set,
-            MapValue {
-                value: set_txn.value,
-                ttl: set_txn.ttl.unwrap_or(ttl),
-                timestamp,
-            },
-        ),
-        Transaction::DeleteTx(delete_txn) => lock.remove(&delete_txn.delete),
+        Transaction::SetTx(set_txn) => {
+            let version = lock.get(&set_txn.set).map(|v| v.version + 1).unwrap_or(1);
+            let key = set_txn.set.clone();
+            let value = set_txn.value.clone();
+            let ttl = set_txn.ttl.unwrap_or(ttl);
+            let remaining_reads = set_txn.views;
+            lock.insert(
+                set_txn.set,
+                MapValue {
+                    value: set_txn.value,
+                    ttl,
+                    timestamp,
+                    version,
+                    remaining_reads,
+                },
+            );
+            on_set(map, key, value, ttl, version, remaining_reads);
+        }
+        Transaction::DeleteTx(delete_txn) => {
+            if lock.remove(&delete_txn.delete).is_some() {
+                on_delete(map, delete_txn.delete);
+            }
+        }
     };
 }
 
+#[derive(Deserialize)]
+struct VersionOpts {
+    if_version: Option<u64>,
+}
+
 #[patch("/{key}")]
 async fn patch_key(
+    req: HttpRequest,
     data: String,
     web::Path(key): web::Path<String>,
+    query: web::Query<VersionOpts>,
     st_map: web::Data<Map>,
 ) -> impl Responder {
     let mut lock = st_map.map.lock().unwrap();
+    let current_version = lock.get(&key).map(|v| v.version).unwrap_or(0);
+    if let Some(expected) = requested_version(&req, query.if_version) {
+        if expected != current_version {
+            return HttpResponse::PreconditionFailed().body("Version mismatch");
+        }
+    }
     if data.starts_with('+') | data.starts_with('-') {
         let increment =
             if data.starts_with('+') { 1 } else { -1 } * data[1..].parse::<i64>().unwrap();
@@ -284,25 +580,52 @@ async fn patch_key(
                     Some(mut it) => {
                         it += increment;
                         v.value = serde_json::json!(it);
-                        Some(it)
+                        v.version += 1;
+                        let ttl_left = v.ttl.saturating_sub(v.timestamp.elapsed().as_secs());
+                        Some((it, v.version, ttl_left, v.remaining_reads))
                     }
                     None => None,
                 };
                 match found_key {
-                    Some(val) => HttpResponse::Ok().body(format!("{}", val)),
+                    Some((val, version, ttl, remaining_reads)) => {
+                        on_set(
+                            &st_map,
+                            key,
+                            serde_json::json!(val),
+                            ttl,
+                            version,
+                            remaining_reads,
+                        );
+                        HttpResponse::Ok()
+                            .append_header(("ETag", version.to_string()))
+                            .body(format!("{}", val))
+                    }
                     None => HttpResponse::BadRequest().body("Value is not a number"),
                 }
             }
             None => {
+                let version = 1;
                 lock.insert(
-                    key,
+                    key.clone(),
                     MapValue {
                         value: serde_json::json!(1),
                         ttl: TTL_DEFAULT,
                         timestamp: Instant::now(),
+                        version,
+                        remaining_reads: None,
                     },
                 );
-                HttpResponse::Ok().body(format!("{}", 1))
+                on_set(
+                    &st_map,
+                    key,
+                    serde_json::json!(1),
+                    TTL_DEFAULT,
+                    version,
+                    None,
+                );
+                HttpResponse::Ok()
+                    .append_header(("ETag", version.to_string()))
+                    .body(format!("{}", 1))
             }
         }
     } else {
@@ -311,10 +634,215 @@ async fn patch_key(
 }
 
 #[delete("/{key}")]
-async fn delete_key(web::Path(key): web::Path<String>, st_map: web::Data<Map>) -> impl Responder {
+async fn delete_key(
+    req: HttpRequest,
+    web::Path(key): web::Path<String>,
+    query: web::Query<VersionOpts>,
+    st_map: web::Data<Map>,
+) -> impl Responder {
     let mut lock = st_map.map.lock().unwrap();
+    let current_version = lock.get(&key).map(|v| v.version).unwrap_or(0);
+    if let Some(expected) = requested_version(&req, query.if_version) {
+        if expected != current_version {
+            return HttpResponse::PreconditionFailed().body("Version mismatch");
+        }
+    }
     match lock.remove(&key) {
-        Some(_) => HttpResponse::Ok().body("Key removed"),
+        Some(_) => {
+            on_delete(&st_map, key);
+            HttpResponse::Ok().body("Key removed")
+        }
         None => HttpResponse::NotFound().body("Key not found"),
     }
 }
+
+/// Turn a subscription's change events into a stream of SSE frames, filtered
+/// to keys under `prefix`. A keep-alive comment is interleaved on
+/// `WATCH_KEEP_ALIVE` so idle connections aren't closed by intermediaries,
+/// and a slow subscriber that falls behind the bounded broadcast buffer is
+/// dropped rather than stalling writers.
+fn watch_stream(
+    rx: broadcast::Receiver<ChangeEvent>,
+    prefix: String,
+) -> impl Stream<Item = Result<web::Bytes, Error>> {
+    let keep_alive = tokio::time::interval(WATCH_KEEP_ALIVE);
+    stream::unfold(
+        (rx, keep_alive, prefix),
+        |(mut rx, mut keep_alive, prefix)| async move {
+            loop {
+                tokio::select! {
+                    event = rx.recv() => match event {
+                        Ok(ev) if ev.key.starts_with(&prefix) => {
+                            let frame = format!("data: {}\n\n", serde_json::to_string(&ev).unwrap());
+                            return Some((Ok(web::Bytes::from(frame)), (rx, keep_alive, prefix)));
+                        }
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(_)) => return None,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = keep_alive.tick() => {
+                        return Some((Ok(web::Bytes::from(": keep-alive\n\n")), (rx, keep_alive, prefix)));
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[get("/watch")]
+async fn watch_all(st_map: web::Data<Map>) -> impl Responder {
+    let rx = st_map.changes.subscribe();
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        // Telling the global `Compress` middleware the body is already
+        // encoded makes it pass frames through untouched instead of
+        // buffering them in its compressor window, which would otherwise
+        // hold back `data:`/keep-alive frames and defeat the stream.
+        .insert_header((header::CONTENT_ENCODING, "identity"))
+        .streaming(watch_stream(rx, String::new()))
+}
+
+#[get("/watch/{prefix}")]
+async fn watch_prefix(
+    web::Path(prefix): web::Path<String>,
+    st_map: web::Data<Map>,
+) -> impl Responder {
+    let rx = st_map.changes.subscribe();
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CONTENT_ENCODING, "identity"))
+        .streaming(watch_stream(rx, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(key: &str, version: u64, remaining_reads: Option<u64>) -> (String, MapValue) {
+        (
+            key.to_string(),
+            MapValue {
+                value: serde_json::json!(key),
+                ttl: 3600,
+                timestamp: Instant::now(),
+                version,
+                remaining_reads,
+            },
+        )
+    }
+
+    fn range(prefix: Option<&str>, start: Option<&str>, end: Option<&str>) -> RangeSpec {
+        RangeSpec {
+            prefix: prefix.map(String::from),
+            start: start.map(String::from),
+            end: end.map(String::from),
+            limit: None,
+            skip: None,
+            reverse: None,
+            values: None,
+        }
+    }
+
+    fn keys_in(lock: &HashMap<String, MapValue>, range: &RangeSpec) -> Vec<String> {
+        select_range(lock, range).map(|(k, _)| k.clone()).collect()
+    }
+
+    #[test]
+    fn select_range_filters_by_prefix() {
+        let lock: HashMap<String, MapValue> = [
+            value("a/1", 1, None),
+            value("a/2", 1, None),
+            value("b/1", 1, None),
+        ]
+        .into_iter()
+        .collect();
+
+        let spec = range(Some("a/"), None, None);
+        assert_eq!(keys_in(&lock, &spec), vec!["a/1", "a/2"]);
+    }
+
+    #[test]
+    fn select_range_respects_start_and_end_bounds() {
+        let lock: HashMap<String, MapValue> = [
+            value("a", 1, None),
+            value("b", 1, None),
+            value("c", 1, None),
+            value("d", 1, None),
+        ]
+        .into_iter()
+        .collect();
+
+        // `start` is inclusive, `end` is exclusive.
+        let spec = range(None, Some("b"), Some("d"));
+        assert_eq!(keys_in(&lock, &spec), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn select_range_reverse_flips_order_after_sorting() {
+        let lock: HashMap<String, MapValue> = [value("a", 1, None), value("b", 1, None)]
+            .into_iter()
+            .collect();
+
+        let mut spec = range(None, None, None);
+        spec.reverse = Some(true);
+        assert_eq!(keys_in(&lock, &spec), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn select_range_skips_dead_keys() {
+        let lock: HashMap<String, MapValue> =
+            [value("alive", 1, None), value("burned", 1, Some(0))]
+                .into_iter()
+                .collect();
+
+        let spec = range(None, None, None);
+        assert_eq!(keys_in(&lock, &spec), vec!["alive"]);
+    }
+
+    #[test]
+    fn first_version_mismatch_none_when_all_match() {
+        let lock: HashMap<String, MapValue> = [value("a", 3, None)].into_iter().collect();
+        let txn = vec![Transaction::SetTx(SetTx {
+            set: "a".to_string(),
+            value: serde_json::json!("new"),
+            ttl: None,
+            if_version: Some(3),
+            views: None,
+        })];
+        assert_eq!(first_version_mismatch(&txn, &lock), None);
+    }
+
+    #[test]
+    fn first_version_mismatch_reports_first_stale_key() {
+        let lock: HashMap<String, MapValue> = [value("a", 3, None), value("b", 5, None)]
+            .into_iter()
+            .collect();
+        let txn = vec![
+            Transaction::SetTx(SetTx {
+                set: "a".to_string(),
+                value: serde_json::json!("new"),
+                ttl: None,
+                if_version: Some(3),
+                views: None,
+            }),
+            Transaction::DeleteTx(DeleteTx {
+                delete: "b".to_string(),
+                if_version: Some(1),
+            }),
+        ];
+        assert_eq!(first_version_mismatch(&txn, &lock), Some("b"));
+    }
+
+    #[test]
+    fn first_version_mismatch_treats_missing_key_as_version_zero() {
+        let lock: HashMap<String, MapValue> = HashMap::new();
+        let txn = vec![Transaction::SetTx(SetTx {
+            set: "missing".to_string(),
+            value: serde_json::json!("new"),
+            ttl: None,
+            if_version: Some(1),
+            views: None,
+        })];
+        assert_eq!(first_version_mismatch(&txn, &lock), Some("missing"));
+    }
+}
@@ -0,0 +1,375 @@
+//! Append-only write-ahead log with periodic snapshot folding, so a `Map`
+//! can survive a restart instead of living only in its in-memory
+//! `Mutex<HashMap>`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::MapValue;
+
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.jsonl.tmp";
+const LOG_FILE: &str = "wal.log";
+// Holds the `seq` of the last record folded into `SNAPSHOT_FILE`, so a WAL
+// left un-truncated by a crash mid-compaction doesn't get replayed a
+// second time on top of the snapshot that already reflects it.
+const COMPACT_MARKER_FILE: &str = "compact.marker";
+
+/// A single durable mutation. `Instant` has no stable on-disk
+/// representation, so expiry is stored as a wall-clock unix timestamp and
+/// turned back into a relative TTL on load. `seq` is a monotonically
+/// increasing counter across every record this `Store` has ever appended,
+/// used to tell a stale WAL record (already folded into the snapshot) apart
+/// from a genuine one.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Set {
+        seq: u64,
+        key: String,
+        value: serde_json::Value,
+        ttl: u64,
+        expires_at: u64,
+        version: u64,
+        remaining_reads: Option<u64>,
+    },
+    Delete {
+        seq: u64,
+        key: String,
+    },
+}
+
+impl Record {
+    fn seq(&self) -> u64 {
+        match self {
+            Record::Set { seq, .. } => *seq,
+            Record::Delete { seq, .. } => *seq,
+        }
+    }
+}
+
+struct LogState {
+    file: File,
+    next_seq: u64,
+}
+
+pub struct Store {
+    dir: PathBuf,
+    log: Mutex<LogState>,
+}
+
+impl Store {
+    pub fn open(dir: &Path) -> io::Result<Store> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        // Resume the sequence counter past whatever this WAL already holds,
+        // so a restart never reissues a `seq` that a marker has already
+        // treated as folded into the snapshot.
+        let next_seq =
+            last_seq_in(&dir.join(LOG_FILE))?.max(read_marker(&dir.join(COMPACT_MARKER_FILE))) + 1;
+        Ok(Store {
+            dir: dir.to_path_buf(),
+            log: Mutex::new(LogState { file, next_seq }),
+        })
+    }
+
+    /// Replay the snapshot then the WAL tail, dropping any entry whose
+    /// persisted expiry has already passed and skipping WAL records the
+    /// compaction marker says are already reflected in the snapshot.
+    pub fn load(&self) -> io::Result<HashMap<String, MapValue>> {
+        let mut map = HashMap::new();
+        // The snapshot itself has no cutoff to respect: every record in it
+        // is, by construction, the current state of that key as of the
+        // last compaction, and always applies regardless of `seq`.
+        replay_snapshot(&mut map, &self.dir.join(SNAPSHOT_FILE))?;
+        let cutoff = read_marker(&self.dir.join(COMPACT_MARKER_FILE));
+        replay_into(&mut map, &self.dir.join(LOG_FILE), cutoff)?;
+        Ok(map)
+    }
+
+    pub fn append_set(
+        &self,
+        key: &str,
+        value: &serde_json::Value,
+        ttl: u64,
+        version: u64,
+        remaining_reads: Option<u64>,
+    ) {
+        self.append(|seq| Record::Set {
+            seq,
+            key: key.to_string(),
+            value: value.clone(),
+            ttl,
+            expires_at: now_unix() + ttl,
+            version,
+            remaining_reads,
+        });
+    }
+
+    pub fn append_delete(&self, key: &str) {
+        self.append(|seq| Record::Delete {
+            seq,
+            key: key.to_string(),
+        });
+    }
+
+    fn append(&self, build: impl FnOnce(u64) -> Record) {
+        let mut state = self.log.lock().unwrap();
+        let seq = state.next_seq;
+        let record = build(seq);
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(state.file, "{}", line);
+            let _ = state.file.flush();
+            state.next_seq = seq + 1;
+        }
+    }
+
+    /// Fold the current map state into a fresh snapshot and truncate the
+    /// WAL, bounding its growth for workloads that churn the same keys.
+    ///
+    /// The marker is written *before* the WAL is truncated: if the process
+    /// crashes in between, the next `load` sees a marker whose cutoff
+    /// already covers every record still sitting in the untruncated WAL,
+    /// so that stale tail is skipped instead of being replayed a second
+    /// time on top of the snapshot it already produced.
+    pub fn compact(&self, current: &HashMap<String, MapValue>) -> io::Result<()> {
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut tmp = File::create(&tmp_path)?;
+        for (key, val) in current.iter() {
+            let remaining = val.ttl.saturating_sub(val.timestamp.elapsed().as_secs());
+            let record = Record::Set {
+                seq: 0,
+                key: key.clone(),
+                value: val.value.clone(),
+                ttl: val.ttl,
+                expires_at: now_unix() + remaining,
+                version: val.version,
+                remaining_reads: val.remaining_reads,
+            };
+            writeln!(tmp, "{}", serde_json::to_string(&record).unwrap())?;
+        }
+        tmp.flush()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        let mut state = self.log.lock().unwrap();
+        let cutoff = state.next_seq.saturating_sub(1);
+        write_marker(&self.dir.join(COMPACT_MARKER_FILE), cutoff)?;
+        state.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.dir.join(LOG_FILE))?;
+        Ok(())
+    }
+}
+
+/// Apply every record in `path` unconditionally, in order.
+fn replay_snapshot(map: &mut HashMap<String, MapValue>, path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        if let Ok(record) = serde_json::from_str::<Record>(&line) {
+            apply_record(map, record);
+        }
+    }
+    Ok(())
+}
+
+/// Apply every record in `path` whose `seq` is past `cutoff`, skipping any
+/// WAL tail the compaction marker says is already folded into the snapshot.
+fn replay_into(map: &mut HashMap<String, MapValue>, path: &Path, cutoff: u64) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        if let Ok(record) = serde_json::from_str::<Record>(&line) {
+            if record.seq() <= cutoff {
+                continue;
+            }
+            apply_record(map, record);
+        }
+    }
+    Ok(())
+}
+
+fn apply_record(map: &mut HashMap<String, MapValue>, record: Record) {
+    match record {
+        Record::Set {
+            key,
+            value,
+            ttl,
+            expires_at,
+            version,
+            remaining_reads,
+            ..
+        } => {
+            let now = now_unix();
+            if expires_at <= now || remaining_reads == Some(0) {
+                map.remove(&key);
+                return;
+            }
+            map.insert(
+                key,
+                MapValue {
+                    value,
+                    ttl: expires_at - now,
+                    timestamp: Instant::now(),
+                    version,
+                    remaining_reads,
+                },
+            );
+        }
+        Record::Delete { key, .. } => {
+            map.remove(&key);
+        }
+    }
+}
+
+/// The `seq` of the marker's cutoff, or 0 if no compaction has happened yet.
+fn read_marker(path: &Path) -> u64 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn write_marker(path: &Path, cutoff: u64) -> io::Result<()> {
+    fs::write(path, cutoff.to_string())
+}
+
+/// The highest `seq` already present in a WAL file, used on startup to
+/// avoid reissuing a `seq` a prior run already handed out.
+fn last_seq_in(path: &Path) -> io::Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let mut max_seq = 0;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if let Ok(record) = serde_json::from_str::<Record>(&line) {
+            max_seq = max_seq.max(record.seq());
+        }
+    }
+    Ok(max_seq)
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rust_keyvaldb_test_{}_{}_{}",
+            name,
+            std::process::id(),
+            now_unix()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn round_trips_a_set_through_restart() {
+        let dir = temp_dir("round_trip");
+        let store = Store::open(&dir).unwrap();
+        store.append_set("k", &serde_json::json!("v"), 3600, 1, None);
+
+        let reopened = Store::open(&dir).unwrap();
+        let map = reopened.load().unwrap();
+        assert_eq!(map.get("k").unwrap().value, serde_json::json!("v"));
+        assert_eq!(map.get("k").unwrap().version, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete_after_set_leaves_no_key_on_replay() {
+        let dir = temp_dir("delete");
+        let store = Store::open(&dir).unwrap();
+        store.append_set("k", &serde_json::json!("v"), 3600, 1, None);
+        store.append_delete("k");
+
+        let reopened = Store::open(&dir).unwrap();
+        let map = reopened.load().unwrap();
+        assert!(map.get("k").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_marker_prevents_replaying_the_wal_twice() {
+        let dir = temp_dir("compact");
+        let store = Store::open(&dir).unwrap();
+        store.append_set("k", &serde_json::json!("v1"), 3600, 1, None);
+
+        let mut current = HashMap::new();
+        current.insert(
+            "k".to_string(),
+            MapValue {
+                value: serde_json::json!("v1"),
+                ttl: 3600,
+                timestamp: Instant::now(),
+                version: 1,
+                remaining_reads: None,
+            },
+        );
+        store.compact(&current).unwrap();
+
+        // Simulate a crash between the snapshot rename and the WAL
+        // truncate: leave a WAL record at the already-folded `seq: 1`
+        // behind, then append a genuinely new record behind it. The stale
+        // record carries a value the snapshot doesn't, so replaying it
+        // would show up in the assertion below.
+        fs::write(
+            dir.join(LOG_FILE),
+            format!(
+                "{}\n",
+                serde_json::to_string(&Record::Set {
+                    seq: 1,
+                    key: "k".to_string(),
+                    value: serde_json::json!("stale"),
+                    ttl: 3600,
+                    expires_at: now_unix() + 3600,
+                    version: 1,
+                    remaining_reads: None,
+                })
+                .unwrap()
+            ),
+        )
+        .unwrap();
+
+        let reopened = Store::open(&dir).unwrap();
+        reopened.append_set("other", &serde_json::json!("v2"), 3600, 1, None);
+        let map = reopened.load().unwrap();
+
+        // The stale `seq: 1` record is skipped by the marker; only the
+        // snapshot's view of "k" and the genuinely new "other" survive.
+        assert_eq!(map.get("k").unwrap().value, serde_json::json!("v1"));
+        assert_eq!(map.get("other").unwrap().value, serde_json::json!("v2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
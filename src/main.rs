@@ -1,29 +1,89 @@
 extern crate actix_web;
 extern crate env_logger;
 
-use actix_web::middleware::Logger;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use actix_web::middleware::{Compress, Logger};
 use actix_web::{web, App, HttpServer};
 use rust_keyvaldb::handlers;
 use rust_keyvaldb::handlers::Map;
 
+// How often the WAL is folded into a fresh snapshot when persistence is on.
+const COMPACT_INTERVAL: Duration = Duration::from_secs(300);
+// Defaults for the background TTL reaper, overridable via env vars below.
+const DEFAULT_REAP_INTERVAL_SECS: u64 = 60;
+const DEFAULT_REAP_BATCH_SIZE: usize = 1000;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
     let prefix = "/api";
-    let data = web::Data::new(Map::new());
+    let persist_dir = std::env::var_os("KVDB_DATA_DIR").map(PathBuf::from);
+    let persistent = persist_dir.is_some();
+    let data = web::Data::new(Map::new(persist_dir)?);
+
+    if persistent {
+        let compact_data = data.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(COMPACT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = compact_data.compact() {
+                    log::error!("WAL compaction failed: {}", e);
+                }
+            }
+        });
+    }
+
+    let reap_interval = std::env::var("KVDB_REAP_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(DEFAULT_REAP_INTERVAL_SECS));
+    let reap_batch_size = std::env::var("KVDB_REAP_BATCH_SIZE")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_REAP_BATCH_SIZE);
+    let reap_data = data.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(reap_interval);
+        loop {
+            interval.tick().await;
+            let reaped = reap_data.reap_expired(reap_batch_size);
+            if reaped > 0 {
+                log::debug!("TTL reaper evicted {} expired key(s)", reaped);
+            }
+        }
+    });
 
     HttpServer::new(move || {
         App::new()
+            // Negotiates gzip/brotli/zstd against the client's
+            // Accept-Encoding; which codecs are actually compiled in is
+            // controlled by this crate's compress-gzip/brotli/zstd features.
+            .wrap(Compress::default())
             .wrap(Logger::default())
             .app_data(data.clone())
             .service(
                 web::scope(prefix)
+                    // Must be registered before `get_val`'s `/{key}`, for
+                    // the same registration-order reason as `batch_read`
+                    // below: a later `/watch` would be swallowed by `/{key}`.
+                    .service(handlers::watch_all)
                     .service(handlers::get_val)
                     .service(handlers::insert_key_txn)
+                    // Must be registered before `insert_key`'s `/{key}`:
+                    // actix matches scope resources in registration order
+                    // with no static-over-dynamic priority, so a later
+                    // `/batch-read` would be swallowed by `/{key}`.
+                    .service(handlers::batch_read)
                     .service(handlers::insert_key)
                     .service(handlers::patch_key)
-                    .service(handlers::list_keys),
+                    .service(handlers::delete_key)
+                    .service(handlers::list_keys)
+                    .service(handlers::watch_prefix),
             )
     })
     .bind(("127.0.0.1", 8080))?